@@ -1,9 +1,17 @@
 use std::path::{PathBuf, Path};
-use std::io::Read;
-use std::process::{Command};
+use std::io::{Read, Write, BufWriter, BufReader, BufRead};
+use std::fs::File;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use anyhow::Result;
+use anyhow::{Result, Context};
 use structopt::StructOpt;
+use serde::{Serialize, Deserialize};
+use libc;
 use pty;
 use pty::fork::{Fork, Master};
 use which::which;
@@ -17,6 +25,50 @@ use crate::validators::path_readable_file;
 /// The verbosity level when no `-q` or `-v` arguments are given, with `0` being `-q`
 pub const DEFAULT_VERBOSITY: u64 = 1;
 
+structopt::clap::arg_enum! {
+    /// How ANSI SGR/CSI sequences in the captured pty output should be handled when writing
+    /// them to `--output-file`.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Color {
+        /// Write the pty's bytes through unmodified, even when `outpath` is a plain file.
+        Always,
+        /// Strip CSI and two-byte ESC sequences before writing, leaving plain text.
+        Never,
+        /// Keep escape sequences when `outpath` looks like a tty-like sink, strip them otherwise.
+        Auto,
+    }
+}
+
+/// Format version of the session file written by [`record`] and read by [`replay`].
+///
+/// Bump this if the header or event shape changes in a way old readers can't cope with.
+const SESSION_VERSION: u32 = 1;
+
+/// The header line of a session file: a single JSON object describing the recording, followed
+/// on subsequent lines by one JSON-encoded [`Event`] per pty read.
+#[derive(Debug, Serialize, Deserialize)]
+struct SessionHeader {
+    version: u32,
+    width: u16,
+    height: u16,
+    timestamp: u64,
+    command: Vec<String>,
+}
+
+/// One recorded chunk of pty output, `elapsed_seconds` after the start of the session.
+///
+/// Serializes as the asciinema-style triple `[elapsed_seconds, "o", payload]`.
+#[derive(Debug, Serialize, Deserialize)]
+struct Event(f64, EventKind, String);
+
+/// The kind of a recorded [`Event`]. Only terminal output is captured today; a `kind` field
+/// leaves room for e.g. input events without breaking the file format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+enum EventKind {
+    #[serde(rename = "o")]
+    Output,
+}
+
 /// Command-line argument schema
 ///
 /// ## Relevant Conventions:
@@ -44,63 +96,569 @@ pub const DEFAULT_VERBOSITY: u64 = 1;
 ///    the top-level help output's list of subcommands.
 #[derive(StructOpt, Debug)]
 #[structopt(template = HELP_TEMPLATE,
-            about = "Run a command in a pty, capturing the colorized output to a file.",
+            about = "Run a command in a pty, recording or replaying its colorized output.",
             global_setting = structopt::clap::AppSettings::ColoredHelp)]
 pub struct CliOpts {
     #[allow(clippy::missing_docs_in_private_items)] // StructOpt compile-time errors if we doc this
     #[structopt(flatten)]
     pub boilerplate: BoilerplateOpts,
 
+    #[structopt(subcommand)]
+    command: Subcommand,
+}
+
+/// The two things this tool can do: record a session, or play one back.
+#[derive(StructOpt, Debug)]
+enum Subcommand {
+    /// Run a command in a pty and record its output to a session file.
+    Record(RecordOpts),
+    /// Replay a previously recorded session file to stdout.
+    Replay(ReplayOpts),
+}
+
+/// Arguments for `record`.
+#[derive(StructOpt, Debug)]
+struct RecordOpts {
     #[structopt(parse(from_os_str), long = "output-file", short = "o")]
     outpath: PathBuf,
 
+    #[structopt(long = "color",
+                possible_values = &Color::variants(),
+                case_insensitive = true,
+                default_value = "auto")]
+    color: Color,
+
+    /// Forward this terminal's stdin to the child and put it in raw mode, for capturing
+    /// interactive programs (shells, editors, pagers). Leave off for line-oriented commands
+    /// like `ls --color` that don't read from stdin.
+    #[structopt(short = "i", long = "interactive")]
+    interactive: bool,
+
     #[structopt(name = "command")]
     command_args: Vec<String>,
 }
 
+/// Arguments for `replay`.
+#[derive(StructOpt, Debug)]
+struct ReplayOpts {
+    #[structopt(parse(from_os_str))]
+    input_file: PathBuf,
+
+    /// Playback speed multiplier; `2.0` plays twice as fast, `0.5` half as fast.
+    #[structopt(long = "speed", default_value = "1.0")]
+    speed: f64,
+
+    /// Cap any single inter-event gap to at most this many seconds.
+    #[structopt(long = "idle-time-limit")]
+    idle_time_limit: Option<f64>,
+}
+
 /// main entrypoint, invoked by our arg parsing boilerplate
+///
+/// The `StructOpt::from_args_safe`-style argument parsing this is invoked from (and its exit
+/// code 2 on parse failure) lives in the shared CLI boilerplate outside this crate's slice of
+/// the tree, not in this file — there's nothing here to change for that half of the request.
 pub async fn main(opts: CliOpts) -> Result<()> {
-    // for command_arg in &opts.command_args {
-    //     println!("{}", command_arg)
-    // }
+    match opts.command {
+        Subcommand::Record(record_opts) => record(&record_opts),
+        Subcommand::Replay(replay_opts) => replay(&replay_opts),
+    }
+}
+
+/// Run `opts.command_args` in a pty and record the session to `opts.outpath` in the cast
+/// format described on [`SessionHeader`] and [`Event`].
+fn record(opts: &RecordOpts) -> Result<()> {
+    let requested_command = opts.command_args.get(0)
+        .ok_or_else(|| anyhow::anyhow!("no command given to record"))?;
 
     // Find binary
-    let command_exists = Path::new(&opts.command_args[0]).exists();
-    let executable_path: PathBuf;
-    if command_exists {
-        executable_path= PathBuf::from(&opts.command_args[0])
+    let command_exists = Path::new(requested_command).exists();
+    let executable_path: PathBuf = if command_exists {
+        PathBuf::from(requested_command)
     } else {
-        executable_path= which(&opts.command_args[0]).unwrap();
+        which(requested_command)
+            .with_context(|| format!("command `{}` not found in PATH", requested_command))?
+    };
+
+    let (width, height) = terminal_size();
+    let header = SessionHeader {
+        version: SESSION_VERSION,
+        width,
+        height,
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .context("system clock is set before the UNIX epoch")?
+            .as_secs(),
+        command: opts.command_args.clone(),
+    };
+
+    // A pipe purely for ordering: the child blocks on it before exec'ing, so the parent's
+    // initial `resize_pty` below is guaranteed to land before the child (or whatever it execs)
+    // ever looks at the pty's size.
+    let mut sync_fds = [0 as libc::c_int; 2];
+    if unsafe { libc::pipe(sync_fds.as_mut_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("failed to create pty-resize sync pipe");
     }
+    let (sync_read_fd, sync_write_fd) = (sync_fds[0], sync_fds[1]);
 
     // Fork child process with a pty
-    let fork = Fork::from_ptmx().unwrap();
+    let fork = Fork::from_ptmx().map_err(|_| anyhow::anyhow!("failed to allocate a pty"))?;
 
-
-    if let Some(mut master) = fork.is_parent().ok() {
+    if let Some(master) = fork.is_parent().ok() {
         // Parent process
-        let mut output = String::new();
-        match master.read_to_string(&mut output) {
-            Ok(_nread) => println!("child tty is: {}", output.trim()),
-            Err(e) => panic!("read error: {}", e),
+        unsafe { libc::close(sync_read_fd) };
+        resize_pty(&master);
+        signal_child_ready(sync_write_fd);
+        record_session(master, &opts.outpath, opts.color, header, opts.interactive)?;
+
+        // The forked pty child `exec`s the target command directly (see the child branch
+        // below), so waiting on it here is waiting on the command itself — its exit status is
+        // what the invoking shell should see as ours. `Fork::wait` only reports the child's pid,
+        // so reap it with `waitpid` directly to get at its status.
+        let child_pid = match fork {
+            Fork::Parent(pid, _) => pid,
+            Fork::Child(_) => unreachable!("is_parent() succeeded"),
+        };
+        let mut raw_status: libc::c_int = 0;
+        while unsafe { libc::waitpid(child_pid, &mut raw_status, 0) } < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() != std::io::ErrorKind::Interrupted {
+                return Err(err).context("failed to wait for the recorded command");
+            }
         }
+        std::process::exit(exit_code_for(std::process::ExitStatus::from_raw(raw_status)));
     } else {
-        // Child process
+        // Child process: stdio is wired to the pty slave so the child believes
+        // it's talking to a real terminal (enabling color, line discipline, etc.). Wait for the
+        // parent to apply the real pty size before exec'ing so full-screen programs don't render
+        // at the stale default.
+        unsafe { libc::close(sync_write_fd) };
+        wait_for_parent_ready(sync_read_fd);
+
         let mut command = Command::new(&executable_path);
         for command_arg in (&opts.command_args).iter().skip(1) {
             command.arg(&command_arg);
         }
-        let status = command.status().expect("could not execute command");
-        std::process::exit(status.code().expect("could not get exit code"));
+        command
+            .stdin(Stdio::inherit())
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit());
+        // `exec` replaces this process's image outright and only returns on failure, so the
+        // forked pty child *is* the target command from here on, rather than a wrapper process
+        // that then forks-and-waits for a grandchild.
+        let exec_err = command.exec();
+        return Err(exec_err)
+            .with_context(|| format!("failed to execute `{}`", executable_path.display()));
+    }
+
+    Ok(())
+}
+
+/// Tell the child waiting in [`wait_for_parent_ready`] that the pty's initial size has been
+/// applied and it's safe to exec now.
+fn signal_child_ready(write_fd: libc::c_int) {
+    let byte = [0u8; 1];
+    unsafe {
+        libc::write(write_fd, byte.as_ptr() as *const libc::c_void, 1);
+        libc::close(write_fd);
+    }
+}
+
+/// Block until [`signal_child_ready`] writes to the other end of the pipe.
+fn wait_for_parent_ready(read_fd: libc::c_int) {
+    let mut byte = [0u8; 1];
+    unsafe {
+        libc::read(read_fd, byte.as_mut_ptr() as *mut libc::c_void, 1);
+        libc::close(read_fd);
+    }
+}
+
+/// Translate a child's `ExitStatus` into a process exit code, using the POSIX shell convention
+/// of `128 + signal` for a child that was killed by a signal rather than exiting normally (where
+/// `status.code()` is `None`).
+fn exit_code_for(status: std::process::ExitStatus) -> i32 {
+    status.code().unwrap_or_else(|| 128 + status.signal().unwrap_or(0))
+}
+
+/// Best-effort size of the terminal we're running in, falling back to the conventional 80x24
+/// default when stdout isn't attached to one.
+fn terminal_size() -> (u16, u16) {
+    let ws = get_winsize(std::io::stdout().as_raw_fd());
+    if ws.ws_col == 0 || ws.ws_row == 0 {
+        (80, 24)
+    } else {
+        (ws.ws_col, ws.ws_row)
+    }
+}
+
+/// Query `fd`'s window size via `TIOCGWINSZ`. Returns a zeroed `winsize` if `fd` isn't a tty.
+fn get_winsize(fd: RawFd) -> libc::winsize {
+    let mut ws: libc::winsize = unsafe { std::mem::zeroed() };
+    unsafe {
+        libc::ioctl(fd, libc::TIOCGWINSZ, &mut ws);
+    }
+    ws
+}
+
+/// Set `master`'s size to the current size of the terminal our own stdout is attached to.
+/// `/dev/tty` is not consulted, so when stdout isn't a tty the pty gets the zeroed size that
+/// [`get_winsize`] returns.
+///
+/// Safe to call from the normal control flow as well as right after a `SIGWINCH`, since it
+/// always re-reads our own winsize rather than trusting stale state.
+fn resize_pty(master: &Master) {
+    let ws = get_winsize(std::io::stdout().as_raw_fd());
+    unsafe {
+        libc::ioctl(master.as_raw_fd(), libc::TIOCSWINSZ, &ws);
+    }
+}
+
+/// Set by [`handle_winch`] and polled from the capture loop, since a signal handler can't
+/// safely do anything beyond flipping a flag.
+static WINCH_RECEIVED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_winch(_signum: libc::c_int) {
+    WINCH_RECEIVED.store(true, Ordering::SeqCst);
+}
+
+/// Install a `SIGWINCH` handler so the pty can be resized whenever our real terminal is.
+fn install_winch_handler() {
+    unsafe {
+        libc::signal(libc::SIGWINCH, handle_winch as libc::sighandler_t);
+        // `signal()` installs with `SA_RESTART` on Linux/glibc, which transparently restarts a
+        // blocking read/poll instead of returning `EINTR` — defeating the `Interrupted` branches
+        // in the capture loop that exist specifically to notice a pending resize promptly.
+        libc::siginterrupt(libc::SIGWINCH, 1);
+    }
+}
+
+/// Puts the real terminal at `fd` into raw mode for the lifetime of the guard, restoring the
+/// original `termios` on drop (including when unwinding from a panic) so a crashing wrapper
+/// never leaves the user's shell in a broken state.
+struct RawModeGuard {
+    fd: RawFd,
+    original: libc::termios,
+}
+
+impl RawModeGuard {
+    fn enable(fd: RawFd) -> Result<Self> {
+        let mut original: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(fd, &mut original) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let mut raw = original;
+        unsafe { libc::cfmakeraw(&mut raw) };
+        if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &raw) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(RawModeGuard { fd, original })
+    }
+}
+
+impl Drop for RawModeGuard {
+    fn drop(&mut self) {
+        unsafe {
+            libc::tcsetattr(self.fd, libc::TCSANOW, &self.original);
+        }
+    }
+}
+
+/// Read the child's output from `master` until it closes, teeing every chunk to our own
+/// stdout (so the wrapper is transparent to watch) and, timestamped relative to session start,
+/// to a cast file at `outpath` (so it can be replayed later with `replay`).
+///
+/// Whether SGR/CSI sequences survive into the recorded events is controlled by `color`; see
+/// [`Color`]. The file is flushed after every event so a crashing child still leaves a usable
+/// partial recording behind.
+///
+/// When `interactive` is set, stdin is also relayed to the pty so the child can be driven
+/// interactively, and our own terminal is put into raw mode for the duration via
+/// [`RawModeGuard`].
+fn record_session(
+    mut master: Master,
+    outpath: &Path,
+    color: Color,
+    header: SessionHeader,
+    interactive: bool,
+) -> Result<()> {
+    let file = File::create(outpath)?;
+    let strip = match color {
+        Color::Always => false,
+        Color::Never => true,
+        // A tty-like sink (e.g. `/dev/stdout` redirected to a real terminal) is assumed to
+        // want its escape sequences kept; anything else is assumed to be a plain log file.
+        Color::Auto => !file.metadata()?.file_type().is_char_device(),
+    };
+    let mut log = BufWriter::new(file);
+    serde_json::to_writer(&mut log, &header)?;
+    log.write_all(b"\n")?;
+    log.flush()?;
+
+    // The pty's initial size was already applied in `record`, synchronized with the child
+    // startup via a pipe; from here we just keep it in sync as our real terminal is resized.
+    install_winch_handler();
+
+    // Raw mode is only meaningful (and only restored) for the lifetime of this relay; holding
+    // the guard in a local keeps that restoration tied to every exit path, panics included.
+    let _raw_mode = if interactive {
+        Some(RawModeGuard::enable(std::io::stdin().as_raw_fd())?)
+    } else {
+        None
+    };
+
+    let master_fd = master.as_raw_fd();
+    let stdin_fd = std::io::stdin().as_raw_fd();
+    // POSIX defines a closed/EOF'd fd as permanently poll-ready, so once stdin hits EOF we must
+    // stop adding it to the poll set entirely or the loop busy-spins reading 0 bytes forever
+    // instead of blocking on the pty.
+    let mut stdin_open = true;
+
+    let mut stdout = std::io::stdout();
+    let mut stripper = AnsiStripper::new();
+    let mut utf8_decoder = Utf8IncrementalDecoder::new();
+    let mut buf = [0u8; 4096];
+    let mut stripped = Vec::with_capacity(buf.len());
+    let start = Instant::now();
+
+    loop {
+        if WINCH_RECEIVED.swap(false, Ordering::SeqCst) {
+            resize_pty(&master);
+        }
+
+        if interactive && stdin_open {
+            // Wait for either side to have something to say before touching either fd, so a
+            // keystroke reaches the child as promptly as the child's own output reaches us.
+            let mut fds = [
+                libc::pollfd { fd: stdin_fd, events: libc::POLLIN, revents: 0 },
+                libc::pollfd { fd: master_fd, events: libc::POLLIN, revents: 0 },
+            ];
+            let ready = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) };
+            if ready < 0 {
+                let err = std::io::Error::last_os_error();
+                if err.kind() == std::io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err.into());
+            }
+            if fds[0].revents & libc::POLLIN != 0 {
+                let mut relay_buf = [0u8; 4096];
+                match std::io::stdin().read(&mut relay_buf) {
+                    Ok(0) => stdin_open = false,
+                    Ok(nread) => master.write_all(&relay_buf[..nread])?,
+                    Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                    Err(_) => stdin_open = false,
+                }
+            }
+            if fds[1].revents & libc::POLLIN == 0 {
+                continue;
+            }
+        } else if interactive {
+            // stdin is closed; block on the pty alone instead of spinning on a dead fd.
+            let mut fds = [libc::pollfd { fd: master_fd, events: libc::POLLIN, revents: 0 }];
+            let ready = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) };
+            if ready < 0 {
+                let err = std::io::Error::last_os_error();
+                if err.kind() == std::io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err.into());
+            }
+            if fds[0].revents & libc::POLLIN == 0 {
+                continue;
+            }
+        }
+
+        let nread = match master.read(&mut buf) {
+            Ok(0) => break,
+            Ok(nread) => nread,
+            // SIGWINCH interrupted the read; loop back around to apply the new size and retry.
+            Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            // The master read errors out (EIO) once the slave side has no more
+            // writers left, which is the normal way a pty session ends.
+            Err(_) => break,
+        };
+        let chunk = &buf[..nread];
+        stdout.write_all(chunk)?;
+        stdout.flush()?;
+
+        let logged_chunk = if strip {
+            stripped.clear();
+            stripper.filter(chunk, &mut stripped);
+            stripped.as_slice()
+        } else {
+            chunk
+        };
+        let payload = utf8_decoder.decode(logged_chunk);
+        if payload.is_empty() {
+            // Nothing decodable yet: the chunk was all escape sequences that got stripped, or a
+            // pending multi-byte sequence still waiting for its rest. Don't record an empty event.
+            continue;
+        }
+        let event = Event(start.elapsed().as_secs_f64(), EventKind::Output, payload);
+        serde_json::to_writer(&mut log, &event)?;
+        log.write_all(b"\n")?;
+        log.flush()?;
+    }
+
+    // Flush any trailing bytes that never got completed into a valid UTF-8 sequence (the
+    // child died mid-character); lossily replace them rather than losing them silently.
+    let tail = utf8_decoder.finish();
+    if !tail.is_empty() {
+        let event = Event(start.elapsed().as_secs_f64(), EventKind::Output, tail);
+        serde_json::to_writer(&mut log, &event)?;
+        log.write_all(b"\n")?;
+        log.flush()?;
     }
 
     Ok(())
 }
 
+/// Decodes a byte stream to UTF-8 across independent chunks, carrying an incomplete trailing
+/// multi-byte sequence over to the next call instead of replacing each half with U+FFFD.
+///
+/// Pty reads land on arbitrary byte boundaries, not UTF-8 character boundaries, so decoding
+/// each chunk in isolation (e.g. `String::from_utf8_lossy`) corrupts any multi-byte character
+/// split across two reads.
+struct Utf8IncrementalDecoder {
+    carry: Vec<u8>,
+}
+
+impl Utf8IncrementalDecoder {
+    fn new() -> Self {
+        Utf8IncrementalDecoder { carry: Vec::new() }
+    }
+
+    /// Decode as much of `carry ++ chunk` as is valid or definitely invalid UTF-8, returning it
+    /// as a `String`. Holds back a trailing incomplete sequence for the next call.
+    fn decode(&mut self, chunk: &[u8]) -> String {
+        self.carry.extend_from_slice(chunk);
+
+        let mut out = String::with_capacity(self.carry.len());
+        let mut offset = 0;
+        loop {
+            match std::str::from_utf8(&self.carry[offset..]) {
+                Ok(valid) => {
+                    out.push_str(valid);
+                    offset = self.carry.len();
+                    break;
+                }
+                Err(e) => {
+                    let valid_up_to = e.valid_up_to();
+                    out.push_str(std::str::from_utf8(&self.carry[offset..offset + valid_up_to]).unwrap());
+                    offset += valid_up_to;
+                    match e.error_len() {
+                        // A genuinely invalid byte sequence (not just truncated): replace it
+                        // and keep decoding the rest of this chunk.
+                        Some(bad_len) => {
+                            out.push('\u{FFFD}');
+                            offset += bad_len;
+                        }
+                        // Looks like the start of a valid sequence that just hasn't arrived in
+                        // full yet; hold it back for the next chunk.
+                        None => break,
+                    }
+                }
+            }
+        }
+
+        self.carry.drain(..offset);
+        out
+    }
+
+    /// Flush whatever incomplete bytes are left over at end of stream, lossily, since no more
+    /// data is coming to complete them.
+    fn finish(&mut self) -> String {
+        if self.carry.is_empty() {
+            return String::new();
+        }
+        let tail = String::from_utf8_lossy(&self.carry).into_owned();
+        self.carry.clear();
+        tail
+    }
+}
+
+/// Read a session file written by [`record`] and re-emit its events to stdout, sleeping for
+/// each event's recorded inter-event delta (scaled by `opts.speed` and capped by
+/// `opts.idle_time_limit`).
+fn replay(opts: &ReplayOpts) -> Result<()> {
+    let file = File::open(&opts.input_file)?;
+    let mut lines = BufReader::new(file).lines();
+
+    let header_line = lines.next().ok_or_else(|| anyhow::anyhow!("session file is empty"))??;
+    let _header: SessionHeader = serde_json::from_str(&header_line)?;
+
+    let mut stdout = std::io::stdout();
+    let mut previous_elapsed = 0.0_f64;
+
+    for line in lines {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let event: Event = serde_json::from_str(&line)?;
+        let delta = (event.0 - previous_elapsed).max(0.0);
+        previous_elapsed = event.0;
+        let delta = match opts.idle_time_limit {
+            Some(limit) => delta.min(limit),
+            None => delta,
+        };
+        std::thread::sleep(Duration::from_secs_f64(delta / opts.speed));
+
+        stdout.write_all(event.2.as_bytes())?;
+        stdout.flush()?;
+    }
+
+    Ok(())
+}
+
+/// Current position within (or outside of) an ANSI escape sequence, tracked across reads so a
+/// CSI sequence split across two pty reads is still recognized and dropped in full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AnsiState {
+    /// Not currently inside an escape sequence; bytes pass through.
+    Normal,
+    /// Just saw ESC (`0x1B`); the next byte decides whether this is a CSI sequence.
+    Escape,
+    /// Inside a CSI sequence (`ESC [ ... final`); dropping bytes until the final byte.
+    Csi,
+}
+
+/// A tiny streaming filter that drops ANSI CSI sequences (`ESC [ ... @`-`~`) and plain two-byte
+/// ESC sequences from a byte stream while passing everything else through untouched.
+struct AnsiStripper {
+    state: AnsiState,
+}
+
+impl AnsiStripper {
+    fn new() -> Self {
+        AnsiStripper { state: AnsiState::Normal }
+    }
+
+    /// Filter `input`, appending surviving bytes to `out`.
+    fn filter(&mut self, input: &[u8], out: &mut Vec<u8>) {
+        for &byte in input {
+            self.state = match self.state {
+                AnsiState::Normal if byte == 0x1B => AnsiState::Escape,
+                AnsiState::Normal => {
+                    out.push(byte);
+                    AnsiState::Normal
+                }
+                AnsiState::Escape if byte == b'[' => AnsiState::Csi,
+                // Any other byte after ESC is a plain two-byte sequence; drop both and resume.
+                AnsiState::Escape => AnsiState::Normal,
+                AnsiState::Csi if (0x40..=0x7E).contains(&byte) => AnsiState::Normal,
+                AnsiState::Csi => AnsiState::Csi,
+            };
+        }
+    }
+}
+
 // Tests go below the code where they'll be out of the way when not the target of attention
 #[cfg(test)]
 mod tests {
-    use super::CliOpts;
+    use super::{exit_code_for, AnsiStripper, CliOpts, Event, EventKind, SessionHeader, Utf8IncrementalDecoder};
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
 
     // TODO: Unit test to verify that the doc comments on `CliOpts` or `BoilerplateOpts` aren't
     // overriding the intended about string.
@@ -110,4 +668,97 @@ mod tests {
     fn test_something() {
         // TODO: Test something
     }
+
+    #[test]
+    fn session_header_round_trips_through_json() {
+        let header = SessionHeader {
+            version: 1,
+            width: 80,
+            height: 24,
+            timestamp: 1_700_000_000,
+            command: vec!["echo".to_string(), "hi".to_string()],
+        };
+        let encoded = serde_json::to_string(&header).unwrap();
+        let decoded: SessionHeader = serde_json::from_str(&encoded).unwrap();
+        assert_eq!(decoded.version, header.version);
+        assert_eq!(decoded.width, header.width);
+        assert_eq!(decoded.height, header.height);
+        assert_eq!(decoded.timestamp, header.timestamp);
+        assert_eq!(decoded.command, header.command);
+    }
+
+    #[test]
+    fn event_serializes_as_asciinema_style_triple() {
+        let event = Event(1.5, EventKind::Output, "hello".to_string());
+        let encoded = serde_json::to_string(&event).unwrap();
+        assert_eq!(encoded, r#"[1.5,"o","hello"]"#);
+
+        let decoded: Event = serde_json::from_str(&encoded).unwrap();
+        assert_eq!(decoded.0, 1.5);
+        assert_eq!(decoded.1, EventKind::Output);
+        assert_eq!(decoded.2, "hello");
+    }
+
+    #[test]
+    fn utf8_decoder_reassembles_multi_byte_char_split_across_chunks() {
+        // '€' is E2 82 AC; split it across two reads the way a pty read boundary would.
+        let bytes = "a€b".as_bytes().to_vec();
+        let mut decoder = Utf8IncrementalDecoder::new();
+
+        let mut out = String::new();
+        out.push_str(&decoder.decode(&bytes[..2]));
+        out.push_str(&decoder.decode(&bytes[2..]));
+
+        assert_eq!(out, "a€b");
+    }
+
+    #[test]
+    fn utf8_decoder_flushes_trailing_incomplete_sequence_on_finish() {
+        let bytes = "a€".as_bytes().to_vec();
+        let mut decoder = Utf8IncrementalDecoder::new();
+
+        // Only the first byte of '€' arrives before the stream ends.
+        let out = decoder.decode(&bytes[..bytes.len() - 2]);
+        assert_eq!(out, "a");
+
+        let tail = decoder.finish();
+        assert_eq!(tail, "\u{FFFD}");
+    }
+
+    #[test]
+    fn ansi_stripper_drops_csi_sgr_sequences() {
+        let mut stripper = AnsiStripper::new();
+        let mut out = Vec::new();
+        stripper.filter(b"\x1b[31mred\x1b[0m", &mut out);
+        assert_eq!(out, b"red");
+    }
+
+    #[test]
+    fn ansi_stripper_drops_two_byte_escape_sequences() {
+        let mut stripper = AnsiStripper::new();
+        let mut out = Vec::new();
+        stripper.filter(b"\x1bcreset", &mut out);
+        assert_eq!(out, b"reset");
+    }
+
+    #[test]
+    fn ansi_stripper_drops_csi_sequence_split_across_calls() {
+        let mut stripper = AnsiStripper::new();
+        let mut out = Vec::new();
+        stripper.filter(b"\x1b[3", &mut out);
+        stripper.filter(b"1mred\x1b[0m", &mut out);
+        assert_eq!(out, b"red");
+    }
+
+    #[test]
+    fn exit_code_for_normal_exit_is_the_exit_code() {
+        let status = ExitStatus::from_raw(42 << 8);
+        assert_eq!(exit_code_for(status), 42);
+    }
+
+    #[test]
+    fn exit_code_for_signal_death_is_128_plus_signal() {
+        let status = ExitStatus::from_raw(9); // killed by SIGKILL
+        assert_eq!(exit_code_for(status), 128 + 9);
+    }
 }